@@ -1,8 +1,9 @@
 use anyhow::Result;
 use clap::Parser;
+use futures_util::StreamExt;
 use rmcp::{
     ServiceExt,
-    model::CallToolRequestParam,
+    model::{CallToolRequestParam, ProtocolVersion, RawContent},
     transport::TokioChildProcess,
     RoleClient,
     service::RunningService,
@@ -19,57 +20,119 @@ use tracing_subscriber::{self, EnvFilter};
 struct Args {
     #[arg(long, env, help = "Anthropic API key (can be set via ANTHROPIC_API_KEY env var or .env file)")]
     anthropic_api_key: Option<String>,
-    
+
     #[arg(long, default_value = "../servers/calculator/target/debug/calculator")]
     calculator_path: String,
+
+    #[arg(long, help = "Run a single turn with this prompt and exit, instead of the interactive loop")]
+    prompt: Option<String>,
+
+    #[arg(long, value_enum, default_value = "text", help = "Output format for one-shot mode")]
+    format: OutputFormat,
+
+    #[arg(long, help = "Disable streaming and wait for the full response before printing (--format json never streams)")]
+    no_stream: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Maximum number of tool-calling round-trips per user turn, to guard against runaway loops.
+const MAX_TOOL_STEPS: u32 = 10;
+
+// MCP protocol versions this client knows how to speak, oldest first.
+const SUPPORTED_PROTOCOL_VERSIONS: &[ProtocolVersion] = &[
+    ProtocolVersion::V_2024_11_05,
+    ProtocolVersion::V_2025_03_26,
+    ProtocolVersion::V_2025_06_18,
+];
+
 // Create MCP client connection to calculator server
 async fn create_calculator_client(calculator_path: &str) -> Result<RunningService<RoleClient, ()>> {
     let cmd = Command::new(calculator_path);
     let transport = TokioChildProcess::new(cmd)?;
-    
+
     let client = ()
         .serve(transport)
         .await?;
-    
+
     // Initialize connection
     let server_info = client.peer_info();
     tracing::info!("Connected to calculator server: {server_info:#?}");
-    
+
+    let negotiated_version = server_info
+        .map(|info| info.protocol_version.clone())
+        .ok_or_else(|| anyhow::anyhow!("Calculator server did not report a protocol version during initialization"))?;
+
+    if !SUPPORTED_PROTOCOL_VERSIONS.contains(&negotiated_version) {
+        return Err(anyhow::anyhow!(
+            "Calculator server advertised unsupported MCP protocol version {negotiated_version:?}; this client supports: {SUPPORTED_PROTOCOL_VERSIONS:?}"
+        ));
+    }
+    tracing::info!("Negotiated MCP protocol version: {negotiated_version:?}");
+
     // List available tools
     let tools = client.list_all_tools().await?;
     tracing::info!("Available calculator tools: {tools:#?}");
-    
+
     Ok(client)
 }
 
-// Call a calculator tool through MCP
-async fn call_calculator_tool(client: &RunningService<RoleClient, ()>, tool_name: &str, arguments: Value) -> Result<String> {
+// Call a calculator tool through MCP. Returns the tool's text output along with whether the
+// server flagged the call as a domain-level failure (CallToolResult.is_error), so callers can
+// forward that flag to Claude instead of only tracking transport-level errors.
+async fn call_calculator_tool(client: &RunningService<RoleClient, ()>, tool_name: &str, arguments: Value) -> Result<(String, Option<bool>)> {
     // Convert Value to object (Map<String, Value>)
     let arguments_obj = if let Value::Object(map) = arguments {
         Some(map)
     } else {
         None
     };
-    
+
     let tool_result = client
         .call_tool(CallToolRequestParam {
             name: tool_name.to_string().into(),
             arguments: arguments_obj,
         })
         .await?;
-        
-    match tool_result.content.first() {
-        Some(content) => Ok(format!("{:?}", content.raw)),
-        None => Ok("No result returned from tool".to_string()),
-    }
+
+    let text = match tool_result.content.first() {
+        Some(content) => match &content.raw {
+            RawContent::Text(text_content) => text_content.text.clone(),
+            other => format!("{:?}", other),
+        },
+        None => "No result returned from tool".to_string(),
+    };
+
+    Ok((text, tool_result.is_error))
+}
+
+// A single block of an Anthropic message. Both sent (text / tool_use / tool_result) and
+// received (text / tool_use) variants round-trip through this one enum so that assistant
+// turns containing tool_use blocks can be pushed straight back onto conversation_history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum ContentBlock {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String, input: Value },
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct AnthropicMessage {
     role: String,
-    content: String,
+    content: Vec<ContentBlock>,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,20 +142,30 @@ struct AnthropicRequest {
     messages: Vec<AnthropicMessage>,
     tools: Vec<Value>,
     tool_choice: Value,
+    stream: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct AnthropicResponse {
-    content: Vec<AnthropicContent>,
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum AnthropicContent {
-    #[serde(rename = "text")]
-    Text { text: String },
-    #[serde(rename = "tool_use")]
-    ToolUse { id: String, name: String, input: Value },
+// A single tool invocation made while answering a turn, for --format json output.
+#[derive(Debug, Clone, Serialize)]
+struct ToolCallRecord {
+    name: String,
+    arguments: Value,
+    result: String,
+    errored: bool,
+}
+
+// The full record of one send_message turn, for --format json output.
+#[derive(Debug, Clone, Serialize)]
+struct TurnRecord {
+    prompt: String,
+    tool_calls: Vec<ToolCallRecord>,
+    response: String,
 }
 
 struct ChatClient {
@@ -100,56 +173,51 @@ struct ChatClient {
     api_key: String,
     calculator: RunningService<RoleClient, ()>,
     conversation_history: Vec<AnthropicMessage>,
+    stream: bool,
 }
 
 impl ChatClient {
-    fn new(api_key: String, calculator: RunningService<RoleClient, ()>) -> Self {
+    fn new(api_key: String, calculator: RunningService<RoleClient, ()>, stream: bool) -> Self {
         let http_client = reqwest::Client::new();
-        
+
         ChatClient {
             http_client,
             api_key,
             calculator,
             conversation_history: Vec::new(),
+            stream,
         }
     }
 
-    async fn send_message(&mut self, user_message: &str) -> Result<String> {
-        // Add user message to history
-        self.conversation_history.push(AnthropicMessage {
-            role: "user".to_string(),
-            content: user_message.to_string(),
-        });
+    // Send a request and wait for the full, buffered response body.
+    async fn send_request_buffered(&self, request: &AnthropicRequest) -> Result<AnthropicResponse> {
+        let response = self.http_client
+            .post("https://api.anthropic.com/v1/messages")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("content-type", "application/json")
+            .json(request)
+            .send()
+            .await?;
 
-        // Get available tools from the MCP server
-        let mcp_tools = self.calculator.list_all_tools().await?;
-        tracing::info!("Retrieved {} tools from MCP server", mcp_tools.len());
-        
-        // Convert MCP tools to Anthropic tool format
-        let tools: Vec<Value> = mcp_tools.iter().map(|tool| {
-            json!({
-                "name": tool.name,
-                "description": tool.description.as_ref(),
-                "input_schema": tool.input_schema
-            })
-        }).collect();
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(anyhow::anyhow!("Anthropic API error: {}", error_text));
+        }
 
-        // Prepare request to Anthropic API
-        let request = AnthropicRequest {
-            model: "claude-3-5-sonnet-20241022".to_string(),
-            max_tokens: 1024,
-            messages: self.conversation_history.clone(),
-            tools,
-            tool_choice: json!({"type": "auto"}),
-        };
+        Ok(response.json().await?)
+    }
 
-        // Send request to Anthropic API
+    // Send a request and consume the server-sent-event stream incrementally, printing text
+    // deltas as they arrive and assembling them back into the same shape as the buffered
+    // response. Tool-use blocks are collected fully before this returns.
+    async fn send_request_streaming(&self, request: &AnthropicRequest) -> Result<AnthropicResponse> {
         let response = self.http_client
             .post("https://api.anthropic.com/v1/messages")
             .header("x-api-key", &self.api_key)
             .header("anthropic-version", "2023-06-01")
             .header("content-type", "application/json")
-            .json(&request)
+            .json(request)
             .send()
             .await?;
 
@@ -158,80 +226,248 @@ impl ChatClient {
             return Err(anyhow::anyhow!("Anthropic API error: {}", error_text));
         }
 
-        let anthropic_response: AnthropicResponse = response.json().await?;
-        
-        // Process response and handle tool calls
-        let mut final_response = String::new();
-        
-        for content in &anthropic_response.content {
-            match content {
-                AnthropicContent::Text { text } => {
-                    final_response.push_str(text);
-                }
-                AnthropicContent::ToolUse { id, name, input } => {
-                    // Call the MCP tool
-                    tracing::info!("Calling tool: {} (id: {}) with input: {:#?}", name, id, input);
-                    
-                    match call_calculator_tool(&self.calculator, name, input.clone()).await {
-                        Ok(tool_result) => {
-                            tracing::info!("Tool result: {:#?}", tool_result);
-                            final_response.push_str(&format!("\n\nCalculation result: {}", tool_result));
+        let mut byte_stream = response.bytes_stream();
+        let mut buffer = String::new();
+        let mut content_blocks = Vec::new();
+        let mut current_text = String::new();
+        let mut current_tool: Option<(String, String, String)> = None;
+        let mut stop_reason = None;
+
+        while let Some(chunk) = byte_stream.next().await {
+            buffer.push_str(&String::from_utf8_lossy(&chunk?));
+
+            while let Some(event_end) = buffer.find("\n\n") {
+                let event = buffer[..event_end].to_string();
+                buffer.drain(..event_end + 2);
+
+                let Some(data) = event.lines().find_map(|line| line.strip_prefix("data:")) else {
+                    continue;
+                };
+                let event_json: Value = serde_json::from_str(data.trim())?;
+
+                match event_json.get("type").and_then(Value::as_str) {
+                    Some("content_block_start") => {
+                        let block = event_json.get("content_block");
+                        if block.and_then(|b| b.get("type")).and_then(Value::as_str) == Some("tool_use") {
+                            let id = block.and_then(|b| b.get("id")).and_then(Value::as_str).unwrap_or_default();
+                            let name = block.and_then(|b| b.get("name")).and_then(Value::as_str).unwrap_or_default();
+                            current_tool = Some((id.to_string(), name.to_string(), String::new()));
+                        } else {
+                            current_text.clear();
                         }
-                        Err(e) => {
-                            let error_msg = format!("Error calling tool {}: {}", name, e);
-                            tracing::error!("{}", error_msg);
-                            final_response.push_str(&format!("\n\n{}", error_msg));
+                    }
+                    Some("content_block_delta") => {
+                        let Some(delta) = event_json.get("delta") else { continue };
+                        match delta.get("type").and_then(Value::as_str) {
+                            Some("text_delta") => {
+                                if let Some(text) = delta.get("text").and_then(Value::as_str) {
+                                    print!("{}", text);
+                                    io::stdout().flush()?;
+                                    current_text.push_str(text);
+                                }
+                            }
+                            Some("input_json_delta") => {
+                                if let Some(partial) = delta.get("partial_json").and_then(Value::as_str) {
+                                    if let Some((_, _, input)) = current_tool.as_mut() {
+                                        input.push_str(partial);
+                                    }
+                                }
+                            }
+                            _ => {}
                         }
                     }
+                    Some("content_block_stop") => {
+                        if let Some((id, name, input)) = current_tool.take() {
+                            let input = if input.is_empty() { json!({}) } else { serde_json::from_str(&input)? };
+                            content_blocks.push(ContentBlock::ToolUse { id, name, input });
+                        } else if !current_text.is_empty() {
+                            content_blocks.push(ContentBlock::Text { text: std::mem::take(&mut current_text) });
+                        }
+                    }
+                    Some("message_delta") => {
+                        if let Some(reason) = event_json.get("delta").and_then(|d| d.get("stop_reason")).and_then(Value::as_str) {
+                            stop_reason = Some(reason.to_string());
+                        }
+                    }
+                    _ => {}
                 }
             }
         }
 
-        // Add assistant response to history
+        Ok(AnthropicResponse { content: content_blocks, stop_reason })
+    }
+
+    async fn send_message(&mut self, user_message: &str) -> Result<TurnRecord> {
+        // Add user message to history
         self.conversation_history.push(AnthropicMessage {
-            role: "assistant".to_string(),
-            content: final_response.clone(),
+            role: "user".to_string(),
+            content: vec![ContentBlock::Text { text: user_message.to_string() }],
         });
 
-        Ok(final_response)
+        // Get available tools from the MCP server
+        let mcp_tools = self.calculator.list_all_tools().await?;
+        tracing::info!("Retrieved {} tools from MCP server", mcp_tools.len());
+
+        // Convert MCP tools to Anthropic tool format
+        let tools: Vec<Value> = mcp_tools.iter().map(|tool| {
+            json!({
+                "name": tool.name,
+                "description": tool.description.as_ref(),
+                "input_schema": tool.input_schema
+            })
+        }).collect();
+
+        let mut final_response = String::new();
+        let mut tool_call_records = Vec::new();
+
+        // Agentic loop: keep sending the conversation back to Claude and dispatching any
+        // tool_use blocks it asks for, until it returns a turn with no more tool calls.
+        for _ in 0..MAX_TOOL_STEPS {
+            let request = AnthropicRequest {
+                model: "claude-3-5-sonnet-20241022".to_string(),
+                max_tokens: 1024,
+                messages: self.conversation_history.clone(),
+                tools: tools.clone(),
+                tool_choice: json!({"type": "auto"}),
+                stream: self.stream,
+            };
+
+            let anthropic_response = if self.stream {
+                self.send_request_streaming(&request).await?
+            } else {
+                self.send_request_buffered(&request).await?
+            };
+
+            // Preserve the assistant's turn verbatim (including tool_use ids) so the
+            // matching tool_result blocks we send next line up correctly.
+            self.conversation_history.push(AnthropicMessage {
+                role: "assistant".to_string(),
+                content: anthropic_response.content.clone(),
+            });
+
+            for block in &anthropic_response.content {
+                if let ContentBlock::Text { text } = block {
+                    final_response.push_str(text);
+                }
+            }
+
+            let tool_uses: Vec<(String, String, Value)> = anthropic_response.content.iter()
+                .filter_map(|block| match block {
+                    ContentBlock::ToolUse { id, name, input } => {
+                        Some((id.clone(), name.clone(), input.clone()))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            if tool_uses.is_empty() || anthropic_response.stop_reason.as_deref() != Some("tool_use") {
+                return Ok(TurnRecord {
+                    prompt: user_message.to_string(),
+                    tool_calls: tool_call_records,
+                    response: final_response,
+                });
+            }
+
+            let mut tool_results = Vec::with_capacity(tool_uses.len());
+            for (id, name, input) in tool_uses {
+                tracing::info!("Calling tool: {} (id: {}) with input: {:#?}", name, id, input);
+
+                let (content, is_error) = match call_calculator_tool(&self.calculator, &name, input.clone()).await {
+                    Ok((result, is_error)) => {
+                        tracing::info!("Tool result: {:#?} (is_error: {:?})", result, is_error);
+                        (result, is_error)
+                    }
+                    Err(e) => {
+                        let error_msg = format!("Error calling tool {}: {}", name, e);
+                        tracing::error!("{}", error_msg);
+                        (error_msg, Some(true))
+                    }
+                };
+
+                tool_call_records.push(ToolCallRecord {
+                    name: name.clone(),
+                    arguments: input,
+                    result: content.clone(),
+                    errored: is_error.unwrap_or(false),
+                });
+
+                tool_results.push(ContentBlock::ToolResult { tool_use_id: id, content, is_error });
+            }
+
+            // Feed the tool results back to Claude as a user turn and loop for its next move.
+            self.conversation_history.push(AnthropicMessage {
+                role: "user".to_string(),
+                content: tool_results,
+            });
+        }
+
+        tracing::warn!("Reached max tool-calling steps ({}) without a final answer", MAX_TOOL_STEPS);
+        Ok(TurnRecord {
+            prompt: user_message.to_string(),
+            tool_calls: tool_call_records,
+            response: final_response,
+        })
     }
 }
 
 async fn run_chat_loop(mut client: ChatClient) -> Result<()> {
-    println!("ðŸ§® Calculator Chat Client");
+    println!("🧮 Calculator Chat Client");
     println!("Ask me to perform calculations and I'll use the calculator MCP server!");
     println!("Type 'quit' or 'exit' to stop.\n");
 
     loop {
         print!("You: ");
         io::stdout().flush()?;
-        
+
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
         let input = input.trim();
-        
+
         if input.is_empty() {
             continue;
         }
-        
+
         if input == "quit" || input == "exit" {
             println!("Goodbye!");
             break;
         }
-        
-        print!("ðŸ¤– Assistant: ");
+
+        print!("🤖 Assistant: ");
         io::stdout().flush()?;
-        
+
+        let streaming = client.stream;
         match client.send_message(input).await {
-            Ok(response) => {
-                println!("{}\n", response);
+            Ok(turn) => {
+                // In streaming mode the text was already printed as deltas arrived.
+                if !streaming {
+                    print!("{}", turn.response);
+                }
+                println!("\n");
             }
             Err(e) => {
                 println!("Error: {}\n", e);
             }
         }
     }
-    
+
+    Ok(())
+}
+
+// Run a single turn for the given prompt and print the result in the requested format, then exit.
+async fn run_one_shot(client: &mut ChatClient, prompt: &str, format: OutputFormat) -> Result<()> {
+    let streaming = client.stream;
+    let turn = client.send_message(prompt).await?;
+
+    match format {
+        // In streaming mode the text was already printed as deltas arrived.
+        OutputFormat::Text => {
+            if !streaming {
+                println!("{}", turn.response);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&turn)?),
+    }
+
     Ok(())
 }
 
@@ -239,14 +475,16 @@ async fn run_chat_loop(mut client: ChatClient) -> Result<()> {
 async fn main() -> Result<()> {
     // Load .env file
     dotenvy::dotenv().ok();
-    
-    // Initialize logging
+
+    // Initialize logging. Diagnostics always go to stderr so stdout stays clean for
+    // --format json output.
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into()))
+        .with_writer(std::io::stderr)
         .init();
 
     let args = Args::parse();
-    
+
     // Check for API key
     let api_key = args.anthropic_api_key.ok_or_else(|| {
         anyhow::anyhow!(
@@ -256,16 +494,22 @@ async fn main() -> Result<()> {
             3. Pass --anthropic-api-key your_key_here"
         )
     })?;
-    
+
     tracing::info!("Starting calculator chat client");
-    
+
     // Create calculator client connection
     let calculator = create_calculator_client(&args.calculator_path).await?;
-    
-    // Create chat client
-    let client = ChatClient::new(api_key, calculator);
-    
-    run_chat_loop(client).await?;
-    
+
+    // Create chat client. --format json always needs a clean, single JSON object on
+    // stdout, so streaming text deltas (which print straight to stdout) are incompatible
+    // with it regardless of --no-stream.
+    let stream = !args.no_stream && args.format == OutputFormat::Text;
+    let mut client = ChatClient::new(api_key, calculator, stream);
+
+    match &args.prompt {
+        Some(prompt) => run_one_shot(&mut client, prompt, args.format).await?,
+        None => run_chat_loop(client).await?,
+    }
+
     Ok(())
 }
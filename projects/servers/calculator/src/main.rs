@@ -1,4 +1,5 @@
 use anyhow::Result;
+use clap::Parser;
 use rmcp::{
     model::ErrorData as McpError, ServerHandler, ServiceExt,
     handler::server::{router::tool::ToolRouter, tool::Parameters},
@@ -10,6 +11,40 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 use tracing_subscriber::{self, EnvFilter};
 
+#[derive(Parser, Debug)]
+#[command(name = "calculator")]
+#[command(about = "A calculator MCP server")]
+struct Args {
+    #[arg(
+        long,
+        default_value = "2024-11-05",
+        help = "MCP protocol version to advertise to clients (e.g. 2024-11-05, 2025-03-26, 2025-06-18)"
+    )]
+    protocol_version: String,
+}
+
+// Protocol versions this server build knows how to advertise, oldest first.
+const SUPPORTED_PROTOCOL_VERSIONS: &[(&str, ProtocolVersion)] = &[
+    ("2024-11-05", ProtocolVersion::V_2024_11_05),
+    ("2025-03-26", ProtocolVersion::V_2025_03_26),
+    ("2025-06-18", ProtocolVersion::V_2025_06_18),
+];
+
+fn parse_protocol_version(raw: &str) -> Result<ProtocolVersion> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .find(|(version, _)| *version == raw)
+        .map(|(_, version)| version.clone())
+        .ok_or_else(|| {
+            let supported: Vec<&str> = SUPPORTED_PROTOCOL_VERSIONS.iter().map(|(version, _)| *version).collect();
+            anyhow::anyhow!(
+                "Unsupported protocol version '{}'. Supported versions: {:?}",
+                raw,
+                supported
+            )
+        })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CalculatorError {
     DivisionByZero,
@@ -31,13 +66,6 @@ impl fmt::Display for CalculatorError {
 
 impl std::error::Error for CalculatorError {}
 
-// Convert our custom error to McpError
-impl From<CalculatorError> for McpError {
-    fn from(err: CalculatorError) -> Self {
-        McpError::invalid_params(err.to_string(), None)
-    }
-}
-
 // Request structures for tools
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct AddRequest {
@@ -86,13 +114,19 @@ pub struct SqrtRequest {
 #[derive(Debug, Clone)]
 pub struct Calculator {
     tool_router: ToolRouter<Self>,
+    protocol_version: ProtocolVersion,
 }
 
 #[tool_router]
 impl Calculator {
     pub fn new() -> Self {
+        Self::with_protocol_version(ProtocolVersion::V_2024_11_05)
+    }
+
+    pub fn with_protocol_version(protocol_version: ProtocolVersion) -> Self {
         Self {
             tool_router: Self::tool_router(),
+            protocol_version,
         }
     }
 
@@ -176,7 +210,9 @@ impl Calculator {
             },
             Err(error) => {
                 tracing::error!("Calculator error in {}: {}", operation, error);
-                Err(error.into())
+                // The tool ran, it just failed its domain logic - report that to the
+                // model as a tool result with is_error set, not a protocol-level McpError.
+                Ok(CallToolResult::error(vec![Content::text(error.to_string())]))
             }
         }
     }
@@ -223,7 +259,7 @@ impl Calculator {
 impl ServerHandler for Calculator {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
-            protocol_version: ProtocolVersion::V_2024_11_05,
+            protocol_version: self.protocol_version.clone(),
             capabilities: ServerCapabilities::builder().enable_tools().build(),
             server_info: Implementation::from_build_env(),
             instructions: Some("A calculator that can perform basic mathematical operations including addition, subtraction, multiplication, division, square, and square root.".to_string()),
@@ -239,12 +275,15 @@ async fn main() -> Result<()> {
         .with_ansi(false)
         .init();
 
-    tracing::info!("Starting Calculator MCP server");
+    let args = Args::parse();
+    let protocol_version = parse_protocol_version(&args.protocol_version)?;
 
-    let service = Calculator::new()
+    tracing::info!("Starting Calculator MCP server (protocol version: {})", args.protocol_version);
+
+    let service = Calculator::with_protocol_version(protocol_version)
         .serve(stdio())
         .await?;
-    
+
     service.waiting().await?;
     
     Ok(())
@@ -306,4 +345,24 @@ mod tests {
         let result = calc.perform_multiplication(f64::INFINITY, 2.0);
         assert!(matches!(result, Err(CalculatorError::InvalidInput { .. })));
     }
+
+    #[test]
+    fn test_format_result_reports_calculator_errors_as_tool_errors() {
+        let calc = Calculator::new();
+
+        // A domain failure should come back as a successful tool call with is_error set,
+        // not an Err, so the model can see what went wrong and recover.
+        let result = calc
+            .format_result(Err::<f64, _>(CalculatorError::DivisionByZero), "division", "10 ÷ 0")
+            .expect("format_result should not produce a protocol-level error");
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(format!("{:?}", result.content[0].raw), format!("{:?}", Content::text("Division by zero is not allowed".to_string()).raw));
+
+        // A successful result should still report is_error as unset/false.
+        let result = calc
+            .format_result(Ok(8.0), "addition", "5 + 3")
+            .expect("format_result should not produce a protocol-level error");
+        assert_ne!(result.is_error, Some(true));
+        assert_eq!(format!("{:?}", result.content[0].raw), format!("{:?}", Content::text("5 + 3 = 8".to_string()).raw));
+    }
 }
\ No newline at end of file